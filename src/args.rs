@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use clap::Parser;
 
 #[derive(Parser)]
@@ -20,5 +22,25 @@ pub struct Args {
     pub max_interval: u64,
     #[arg(long, default_value_t = 100)]
     pub insert_batch_size: usize,
+    // identifies this collector run in the `collector_progress` table so
+    // restarts can resume from the last saved checkpoint instead of
+    // `start_idx`
+    #[arg(long, default_value = "default")]
+    pub job: String,
+    // instead of collecting forward from the checkpoint, walk the gaps in
+    // the job's recorded seq-num coverage and re-collect only those
+    #[arg(long)]
+    pub backfill: bool,
+    // how long to park a key that got rate-limited before trying it again
+    #[arg(long, default_value_t = 300)]
+    pub cooldown: u64,
+    // bind address for the Prometheus /metrics endpoint; metrics are
+    // disabled entirely when this is left unset
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    // on a decode error, attempt a permissive re-parse that recovers
+    // match_seq_num and hero drafts instead of aborting the collector
+    #[arg(long)]
+    pub lenient: bool,
     pub keys: Vec<String>,
 }
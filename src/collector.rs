@@ -1,13 +1,21 @@
-use std::{collections::HashMap, num::NonZeroU8, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap},
+    num::NonZeroU8,
+    sync::Arc,
+    time::Duration,
+};
 
 use itertools::Itertools;
+use primitive_types::U256;
+use serde_json::Value;
 use tokio::time::Instant;
 
 use crate::{
     args::Args,
     client::{Client, RequestError},
-    database::Database,
-    dota2::{full, MatchMask},
+    database::{coalesce_ranges, Database},
+    dota2::{full, MatchMask, Side},
+    metrics::Metrics,
 };
 
 pub struct RateControl {
@@ -46,22 +54,56 @@ impl RateControl {
     pub fn slow_down(&mut self) {
         self.interval = std::cmp::min(self.interval * 2, self.max_interval);
     }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
 }
 
 pub struct Collector {
     match_seq_num: u64,
-    rate: RateControl,
+    // highest match_seq_num this job has ever durably advanced to; unlike
+    // match_seq_num (which run_backfill seeks backward into gaps) this only
+    // ever moves forward, and is the only value ever persisted via
+    // database.save_progress
+    checkpoint: u64,
+    // one RateControl per key so a slow/throttled key doesn't drag down
+    // keys that are still healthy
+    rates: HashMap<String, RateControl>,
+    // keys currently resting off a rate-limit response, and when they're
+    // allowed back into the cycle
+    cooldowns: HashMap<String, Instant>,
+    cooldown: Duration,
     database: Database,
     indices: HashMap<NonZeroU8, Vec<MatchMask>>,
     keys: Vec<String>,
     batch: usize,
     proxy: Option<String>,
+    job: String,
+    // coalesced, sorted [left, right) match_seq_num ranges already collected
+    // for this job, used to find gaps to backfill
+    covered: Vec<(u64, u64)>,
+    backfill: bool,
+    // Some when --metrics-addr was passed; wired into request/save and the
+    // RateControl transitions so operators can scrape and alert on it
+    metrics: Option<Arc<Metrics>>,
+    lenient: bool,
 }
 
 impl Collector {
     pub async fn new(args: Args) -> anyhow::Result<Self> {
-        let match_seq_num = args.start_idx;
-        let rate = RateControl::new(args.min_interval, args.max_interval);
+        let rates = args
+            .keys
+            .iter()
+            .map(|key| {
+                (
+                    key.clone(),
+                    RateControl::new(args.min_interval, args.max_interval),
+                )
+            })
+            .collect();
+        let cooldowns = HashMap::new();
+        let cooldown = Duration::from_secs(args.cooldown);
         let indices = HashMap::with_capacity(256 * 2);
         let database = Database::new(
             args.clickhouse_server.as_deref(),
@@ -70,21 +112,82 @@ impl Collector {
             args.clickhouse_password.as_deref(),
         )
         .await?;
+        let job = args.job;
+        // resume from the last durable checkpoint if we have one; start_idx
+        // is only a floor for a job that has never checkpointed before
+        let match_seq_num = match database.load_progress(&job).await? {
+            Some(checkpoint) => checkpoint,
+            None => args.start_idx,
+        };
+        let checkpoint = match_seq_num;
+        let covered = database.load_ranges(&job).await?;
+        let backfill = args.backfill;
         let keys = args.keys;
         let proxy = args.proxy;
         let batch = args.insert_batch_size;
+        let metrics = args.metrics_addr.map(|addr| {
+            let metrics = Arc::new(Metrics::default());
+            metrics.clone().serve(addr);
+            metrics
+        });
+        let lenient = args.lenient;
 
         Ok(Self {
             match_seq_num,
-            rate,
+            checkpoint,
+            rates,
+            cooldowns,
+            cooldown,
             database,
             indices,
             keys,
             proxy,
             batch,
+            job,
+            covered,
+            backfill,
+            metrics,
+            lenient,
         })
     }
 
+    // true if `key` is still resting off a rate-limit response; clears the
+    // cooldown once it has expired
+    fn cooling_down(&mut self, key: &str) -> bool {
+        match self.cooldowns.get(key) {
+            Some(&until) if Instant::now() < until => true,
+            Some(_) => {
+                self.cooldowns.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // if every one of our keys is currently cooling down, returns the
+    // earliest expiry among them so callers can sleep until a key is
+    // actually usable again instead of busy-looping through the cycle
+    fn all_cooling_down_until(&self) -> Option<Instant> {
+        if self.cooldowns.len() < self.keys.len() {
+            return None;
+        }
+        self.keys
+            .iter()
+            .map(|key| self.cooldowns.get(key).copied())
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .min()
+    }
+
+    fn make_clients(&self) -> anyhow::Result<Vec<(String, Client)>> {
+        // construct clients here because we don't want to do
+        // self-referential stuff
+        self.keys
+            .iter()
+            .map(|key| Ok((key.clone(), Client::new(key, self.proxy.as_deref())?)))
+            .collect()
+    }
+
     fn collect(&mut self, matches: &full::MatchHistory) -> u64 {
         // collect a single batch
         // do we want to do anything else?
@@ -103,7 +206,7 @@ impl Collector {
             })
     }
 
-    pub async fn request(&mut self, client: &Client) -> anyhow::Result<()> {
+    pub async fn request(&mut self, key: &str, client: &Client) -> anyhow::Result<()> {
         // get_match_history is not reliable so we switch back to get_match_history_by_seq_num
         match client.get_match_history_full(self.match_seq_num, 100).await {
             Ok(matches) => {
@@ -113,60 +216,177 @@ impl Collector {
                 log::debug!("retrived {} matches from [{}, {}).", count, left, right);
 
                 // update match_seq_num
-                self.match_seq_num = right;
+                self.advance(right);
 
-                self.rate.speed_up();
+                if right > left {
+                    self.database.save_range(&self.job, left, right).await?;
+                    self.covered.push((left, right));
+                    self.covered = coalesce_ranges(std::mem::take(&mut self.covered));
+                }
+
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_matches(count as u64);
+                }
+
+                let rate = self.rate_for(key);
+                rate.speed_up();
                 if matches.matches.len() < 100 {
                     // this means we're reaching the newest matches, so slowing down a bit
-                    self.rate.slow_down();
+                    rate.slow_down();
+                }
+                self.report_rate(key);
+            }
+            Err(RequestError::RateLimited) => {
+                // park this key instead of hammering it; other keys keep
+                // working at full speed in the meantime
+                log::warn!(
+                    "key rate-limited, cooling down for {}s",
+                    self.cooldown.as_secs()
+                );
+                self.cooldowns
+                    .insert(key.to_string(), Instant::now() + self.cooldown);
+                self.rate_for(key).slow_down();
+                self.report_rate(key);
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_rate_limit();
                 }
             }
             Err(RequestError::DecodeError(err, content)) => {
-                // maybe valve have changed the json response format
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_decode_error();
+                }
+
+                // maybe valve have changed the json response format; in
+                // --lenient mode we try to salvage what we can instead of
+                // quitting on the first unrecognised field
+                if self.lenient {
+                    if let Some(batch) = lenient_parse(&content, self.match_seq_num) {
+                        log::warn!(
+                            "DecodeError: {}; recovered {} matches in lenient mode, unexpected fields: [{}]",
+                            err,
+                            batch.matches,
+                            batch.unexpected_fields.iter().join(", ")
+                        );
+
+                        let (left, right) = (self.match_seq_num, batch.match_seq_num);
+                        for (hero, mask) in batch.masks {
+                            self.indices.entry(hero).or_default().push(mask);
+                        }
+                        self.advance(right);
+
+                        if right > left {
+                            self.database.save_range(&self.job, left, right).await?;
+                            self.covered.push((left, right));
+                            self.covered = coalesce_ranges(std::mem::take(&mut self.covered));
+                        }
+                        if let Some(metrics) = &self.metrics {
+                            metrics.record_matches(batch.matches);
+                            metrics.set_last_seq_num(self.match_seq_num);
+                        }
+
+                        self.rate_for(key).slow_down();
+                        self.report_rate(key);
+                        return Ok(());
+                    }
+                }
+
                 // this is when things really goes wrong, we need to fix it manually
                 log::error!("DecodeError: {}", err);
                 log::info!("Saving response to {}-error.json", self.match_seq_num);
                 let filename = format!("{}-error.json", self.match_seq_num);
                 std::fs::write(filename, content)?;
                 // we have to quit or else we'll end in a dead loop
-                // we could in theory accept unknown fields so we don't have to quit here
-                // but we don't want to
                 return Err(err.into());
             }
             Err(error) => {
                 // similar connection errors
                 log::warn!("RequestError: {}", error);
-                self.rate.slow_down();
+                self.rate_for(key).slow_down();
+                self.report_rate(key);
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_connection_error();
+                }
                 tokio::time::sleep(Duration::from_secs(5)).await;
             }
         }
         Ok(())
     }
 
+    // moves the request cursor to `right` and bumps the durable checkpoint
+    // along with it; checkpoint only ever moves forward, so a backfill run
+    // seeking match_seq_num backward into a gap can never regress it
+    fn advance(&mut self, right: u64) {
+        self.match_seq_num = right;
+        self.checkpoint = std::cmp::max(self.checkpoint, right);
+    }
+
+    fn rate_for(&mut self, key: &str) -> &mut RateControl {
+        self.rates
+            .get_mut(key)
+            .expect("every key in self.keys has a RateControl")
+    }
+
+    fn report_rate(&mut self, key: &str) {
+        let interval = self.rate_for(key).interval();
+        if let Some(metrics) = &self.metrics {
+            // the API key itself must never leave the process: it's a
+            // secret, and this label is served unauthenticated over
+            // --metrics-addr to whatever scrapes it. Export its position
+            // among --keys instead so series stay distinguishable.
+            metrics.set_rate_interval(&self.key_label(key), interval);
+        }
+    }
+
+    // a label safe to export on the metrics endpoint in place of the raw
+    // (secret) API key: its 0-based position among the configured keys
+    fn key_label(&self, key: &str) -> String {
+        match self.keys.iter().position(|candidate| candidate == key) {
+            Some(idx) => idx.to_string(),
+            None => "unknown".to_string(),
+        }
+    }
+
     pub async fn save(&mut self) -> anyhow::Result<()> {
         // saving the full result uses way too much storage space which we can't afford!
         log::debug!("saving indices to database!");
+        let started = Instant::now();
         for (key, masks) in self.indices.iter_mut() {
             self.database.save_indexed_masks(key.get(), masks).await?;
             // clear masks instead of indices so less alloction happens
             masks.clear();
         }
+        // only bump the checkpoint once the masks above are durably saved,
+        // so the checkpoint never runs ahead of persisted data; this is
+        // self.checkpoint (not match_seq_num) so a backfill run seeking
+        // backward into a gap never rolls the forward resume point back
+        self.database
+            .save_progress(&self.job, self.checkpoint)
+            .await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_insert_latency(started.elapsed());
+            metrics.set_last_seq_num(self.checkpoint);
+        }
         Ok(())
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
-        // construct clients here because we don't want to do
-        // self-referential stuff
-        let clients = self
-            .keys
-            .iter()
-            .map(|key| Client::new(key, self.proxy.as_deref()))
-            .collect::<Result<Vec<_>, _>>()?;
+        if self.backfill {
+            return self.run_backfill().await;
+        }
+
+        let clients = self.make_clients()?;
 
         for clts in clients.iter().cycle().chunks(self.batch).into_iter() {
-            for clt in clts {
-                self.rate.wait().await;
-                if let Err(err) = self.request(clt).await {
+            for (key, clt) in clts {
+                if self.cooling_down(key) {
+                    if let Some(until) = self.all_cooling_down_until() {
+                        log::warn!("every key is cooling down, sleeping until one recovers");
+                        tokio::time::sleep_until(until).await;
+                    }
+                    continue;
+                }
+                self.rate_for(key).wait().await;
+                if let Err(err) = self.request(key, clt).await {
                     // request will only fail when decode error happened
                     // in case this happens, we still want to save requested matches
                     self.save().await?;
@@ -179,4 +399,144 @@ impl Collector {
 
         Ok(())
     }
+
+    // walk the complement of `covered` between the smallest and largest seen
+    // match_seq_num and re-request only the missing intervals, so holes left
+    // by aborted batches or connection errors get filled in
+    async fn run_backfill(&mut self) -> anyhow::Result<()> {
+        let clients = self.make_clients()?;
+        let mut clts = clients.iter().cycle();
+
+        let (Some(&(min_seen, _)), Some(&(_, max_seen))) =
+            (self.covered.first(), self.covered.last())
+        else {
+            log::info!("backfill: no covered ranges recorded for this job yet, nothing to do");
+            return Ok(());
+        };
+
+        for (mut left, right) in gaps(&self.covered, min_seen, max_seen) {
+            log::info!("backfill: filling gap [{}, {})", left, right);
+            while left < right {
+                let (key, clt) = clts
+                    .next()
+                    .expect("clients cycles forever over a non-empty list");
+                if self.cooling_down(key) {
+                    if let Some(until) = self.all_cooling_down_until() {
+                        log::warn!("every key is cooling down, sleeping until one recovers");
+                        tokio::time::sleep_until(until).await;
+                    }
+                    continue;
+                }
+                self.rate_for(key).wait().await;
+                self.match_seq_num = left;
+                if let Err(err) = self.request(key, clt).await {
+                    self.save().await?;
+                    return Err(err);
+                }
+                left = self.match_seq_num;
+                self.save().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct LenientBatch {
+    match_seq_num: u64,
+    matches: u64,
+    masks: Vec<(NonZeroU8, MatchMask)>,
+    unexpected_fields: BTreeSet<String>,
+}
+
+// known fields of the GetMatchHistoryBySequenceNum response that we actually
+// need; anything else is recorded as "unexpected" rather than rejected
+const KNOWN_MATCH_FIELDS: [&str; 3] = ["match_id", "match_seq_num", "players"];
+const KNOWN_PLAYER_FIELDS: [&str; 3] = ["account_id", "player_slot", "hero_id"];
+
+// best-effort re-parse of a raw match history response that still recovers
+// match_seq_num and hero drafts even if Valve has added fields our strict
+// deserializer doesn't know about yet. Returns None if even this permissive
+// parse can't make sense of the payload.
+fn lenient_parse(content: &str, floor: u64) -> Option<LenientBatch> {
+    let value: Value = serde_json::from_str(content).ok()?;
+    let matches = value.get("result")?.get("matches")?.as_array()?;
+    let match_count = matches.len() as u64;
+
+    let mut unexpected_fields = BTreeSet::new();
+    let mut match_seq_num = floor;
+    let mut masks = Vec::with_capacity(matches.len());
+
+    for mat in matches {
+        let obj = mat.as_object()?;
+        unexpected_fields.extend(
+            obj.keys()
+                .filter(|field| !KNOWN_MATCH_FIELDS.contains(&field.as_str()))
+                .cloned(),
+        );
+
+        let match_id = obj.get("match_id")?.as_u64()?;
+        let seq_num = obj.get("match_seq_num")?.as_u64()?;
+        let players = obj.get("players")?.as_array()?;
+
+        let mut radiant = U256::zero();
+        let mut dire = U256::zero();
+        let mut hero_ids = Vec::with_capacity(players.len());
+        for player in players {
+            let player = player.as_object()?;
+            unexpected_fields.extend(
+                player
+                    .keys()
+                    .filter(|field| !KNOWN_PLAYER_FIELDS.contains(&field.as_str()))
+                    .cloned(),
+            );
+
+            let hero_id = player.get("hero_id")?.as_u64()? as u8;
+            let player_slot = player.get("player_slot")?.as_u64()? as u8;
+            let hero_mask = U256::one() << U256::from(hero_id);
+            match Side::from(player_slot) {
+                Side::Radiant => radiant |= hero_mask,
+                Side::Dire => dire |= hero_mask,
+            }
+            hero_ids.push(hero_id);
+        }
+
+        let mask = MatchMask {
+            match_id,
+            radiant,
+            dire,
+        };
+        masks.extend(
+            hero_ids
+                .into_iter()
+                .filter_map(NonZeroU8::new)
+                .map(|hero| (hero, mask)),
+        );
+
+        match_seq_num = std::cmp::max(match_seq_num, seq_num + 1);
+    }
+
+    Some(LenientBatch {
+        match_seq_num,
+        matches: match_count,
+        masks,
+        unexpected_fields,
+    })
+}
+
+// complement of `covered` inside [min, max): the ranges that were never
+// collected and still need to be requested
+fn gaps(covered: &[(u64, u64)], min: u64, max: u64) -> Vec<(u64, u64)> {
+    let mut gaps = Vec::new();
+    let mut cursor = min;
+    for &(left, right) in covered {
+        if left > cursor {
+            gaps.push((cursor, left));
+        }
+        cursor = std::cmp::max(cursor, right);
+    }
+    if cursor < max {
+        gaps.push((cursor, max));
+    }
+    gaps
 }
@@ -1,7 +1,7 @@
 use clickhouse::{error::Error, Client};
 use itertools::Itertools;
 
-use crate::dota2::MatchDraft;
+use crate::dota2::{MatchDraft, MatchupStats, SeqNumRange};
 
 pub struct Database {
     database: String,
@@ -39,6 +39,7 @@ impl Database {
                 match_id UInt64,
                 radiant Tuple(UInt8, UInt8, UInt8, UInt8, UInt8),
                 dire Tuple(UInt8, UInt8, UInt8, UInt8, UInt8),
+                radiant_win Nullable(UInt8),
             )
             ENGINE = MergeTree()
             ORDER BY match_id
@@ -48,9 +49,94 @@ impl Database {
         );
         client.query(&query).execute().await?;
 
+        // older deployments created `drafts` before we started tracking the
+        // outcome. Leave the column nullable with no DEFAULT so those rows
+        // come back as NULL instead of a fabricated outcome; the win-rate
+        // queries below explicitly exclude NULL rows rather than trust them.
+        let query = format!(
+            "ALTER TABLE {}.drafts ADD COLUMN IF NOT EXISTS radiant_win Nullable(UInt8);",
+            &database
+        );
+        client.query(&query).execute().await?;
+
+        // checkpoint of the highest match_seq_num a given collector run has
+        // durably saved, so a restart can resume exactly where it stopped
+        // instead of relying on an operator-supplied start_idx
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {}.collector_progress (
+                job String,
+                match_seq_num UInt64,
+                updated_at DateTime,
+            )
+            ENGINE = ReplacingMergeTree(updated_at)
+            ORDER BY job;",
+            &database
+        );
+        client.query(&query).execute().await?;
+
+        // every [left, right) match_seq_num range a job has actually
+        // requested and saved, so gaps left by aborted batches or connection
+        // errors can be found and backfilled instead of silently skipped
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {}.collected_ranges (
+                job String,
+                left UInt64,
+                right UInt64,
+            )
+            ENGINE = MergeTree()
+            ORDER BY (job, left);",
+            &database
+        );
+        client.query(&query).execute().await?;
+
         Ok(Self { database, client })
     }
 
+    /// Returns the highest `match_seq_num` checkpointed for `job`, or `None`
+    /// if this job has never saved a checkpoint before.
+    pub async fn load_progress(&self, job: &str) -> Result<Option<u64>, Error> {
+        let query = format!(
+            "SELECT match_seq_num FROM {}.collector_progress FINAL WHERE job = '{}'",
+            self.database, job
+        );
+        self.client.query(&query).fetch_optional::<u64>().await
+    }
+
+    /// Bumps the checkpoint for `job` to `match_seq_num`. Should only be
+    /// called once the corresponding matches are durably saved, so the
+    /// checkpoint never runs ahead of persisted data.
+    pub async fn save_progress(&self, job: &str, match_seq_num: u64) -> Result<(), Error> {
+        let query = format!(
+            "INSERT INTO {}.collector_progress (job, match_seq_num, updated_at) VALUES ('{}', {}, now());",
+            self.database, job, match_seq_num
+        );
+        self.client.query(&query).execute().await?;
+        Ok(())
+    }
+
+    /// Records that `[left, right)` was successfully collected for `job`.
+    pub async fn save_range(&self, job: &str, left: u64, right: u64) -> Result<(), Error> {
+        let query = format!(
+            "INSERT INTO {}.collected_ranges (job, left, right) VALUES ('{}', {}, {});",
+            self.database, job, left, right
+        );
+        self.client.query(&query).execute().await?;
+        Ok(())
+    }
+
+    /// Loads every `[left, right)` range recorded for `job`, coalesced into
+    /// a compact sorted, non-overlapping `Vec<(u64, u64)>`.
+    pub async fn load_ranges(&self, job: &str) -> Result<Vec<(u64, u64)>, Error> {
+        let query = format!(
+            "SELECT left, right FROM {}.collected_ranges WHERE job = '{}' ORDER BY left",
+            self.database, job
+        );
+        let ranges: Vec<SeqNumRange> = self.client.query(&query).fetch_all().await?;
+        Ok(coalesce_ranges(
+            ranges.into_iter().map(|r| (r.left, r.right)).collect(),
+        ))
+    }
+
     pub async fn query_matches(
         &self,
         team1: &[u8],
@@ -99,4 +185,96 @@ impl Database {
         insert.end().await?;
         Ok(())
     }
+
+    /// Win rate (0.0-1.0) of the radiant side for matches whose radiant side
+    /// contains every hero in `radiant` and whose dire side contains every
+    /// hero in `dire`; other heroes on either side are not checked.
+    pub async fn lineup_win_rate(&self, radiant: &[u8], dire: &[u8]) -> Result<f64, Error> {
+        let side_check = |side: &str, heroes: &[u8]| {
+            format!(
+                "bitmapHasAll(bitmapBuild(array(untuple({}))), bitmapBuild([{}]))",
+                side,
+                heroes.iter().format(","),
+            )
+        };
+
+        let query = format!(
+            "SELECT countIf(radiant_win = 1) / count()
+             FROM {}.drafts
+             WHERE {} AND {} AND radiant_win IS NOT NULL",
+            self.database,
+            side_check("radiant", radiant),
+            side_check("dire", dire),
+        );
+        self.client.query(&query).fetch_one::<f64>().await
+    }
+
+    /// Win rate (0.0-1.0) of `hero` across every match it appears in, on
+    /// either side.
+    pub async fn hero_win_rate(&self, hero: u8) -> Result<f64, Error> {
+        let on_side = |side: &str| {
+            format!(
+                "bitmapHasAll(bitmapBuild(array(untuple({}))), bitmapBuild([{}]))",
+                side, hero
+            )
+        };
+        let (on_radiant, on_dire) = (on_side("radiant"), on_side("dire"));
+
+        let query = format!(
+            "SELECT countIf(({on_radiant} AND radiant_win = 1) OR ({on_dire} AND radiant_win = 0))
+                / countIf({on_radiant} OR {on_dire})
+             FROM {db}.drafts
+             WHERE radiant_win IS NOT NULL",
+            db = self.database,
+        );
+        self.client.query(&query).fetch_one::<f64>().await
+    }
+
+    /// Head-to-head game and win counts for `hero_a` against `hero_b`,
+    /// counting only matches where they were on opposing sides.
+    pub async fn matchup_stats(&self, hero_a: u8, hero_b: u8) -> Result<MatchupStats, Error> {
+        let on_side = |side: &str, hero: u8| {
+            format!(
+                "bitmapHasAll(bitmapBuild(array(untuple({}))), bitmapBuild([{}]))",
+                side, hero
+            )
+        };
+        let a_radiant_b_dire = format!(
+            "({} AND {})",
+            on_side("radiant", hero_a),
+            on_side("dire", hero_b)
+        );
+        let a_dire_b_radiant = format!(
+            "({} AND {})",
+            on_side("dire", hero_a),
+            on_side("radiant", hero_b)
+        );
+
+        let query = format!(
+            "SELECT count() AS games,
+                    countIf(({a_radiant_b_dire} AND radiant_win = 1)
+                         OR ({a_dire_b_radiant} AND radiant_win = 0)) AS hero_a_wins
+             FROM {db}.drafts
+             WHERE ({a_radiant_b_dire} OR {a_dire_b_radiant}) AND radiant_win IS NOT NULL",
+            db = self.database,
+        );
+        self.client.query(&query).fetch_one::<MatchupStats>().await
+    }
+}
+
+/// Merges overlapping/adjacent `[left, right)` ranges into a sorted, compact
+/// set of disjoint ranges.
+pub(crate) fn coalesce_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable_by_key(|&(left, _)| left);
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (left, right) in ranges {
+        match merged.last_mut() {
+            Some((_, last_right)) if left <= *last_right => {
+                *last_right = std::cmp::max(*last_right, right);
+            }
+            _ => merged.push((left, right)),
+        }
+    }
+    merged
 }
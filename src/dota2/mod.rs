@@ -93,4 +93,59 @@ impl From<u64> for MatchId {
     fn from(value: u64) -> Self {
         Self { match_id: value }
     }
+}
+
+/// A half-open `[left, right)` range of `match_seq_num` that a collector run
+/// has actually requested and saved.
+#[derive(Row, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SeqNumRange {
+    pub left: u64,
+    pub right: u64,
+}
+
+/// Row stored in the `drafts` table: a match's hero lineup on each side plus
+/// who won, so aggregate win-rate queries can run server-side. `radiant_win`
+/// is `None` only for rows written before this column existed; win-rate
+/// queries exclude those instead of guessing an outcome for them.
+#[derive(Row, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MatchDraft {
+    pub match_id: u64,
+    pub radiant: (u8, u8, u8, u8, u8),
+    pub dire: (u8, u8, u8, u8, u8),
+    pub radiant_win: Option<bool>,
+}
+
+impl From<&full::Match> for MatchDraft {
+    fn from(value: &full::Match) -> Self {
+        let match_id = value.match_id;
+        let mut radiant = [0u8; 5];
+        let mut dire = [0u8; 5];
+        let (mut radiant_idx, mut dire_idx) = (0, 0);
+        for player in &value.players {
+            match (player.player_slot.into(), player.hero_id) {
+                (Side::Radiant, hero_id) if radiant_idx < radiant.len() => {
+                    radiant[radiant_idx] = hero_id;
+                    radiant_idx += 1;
+                }
+                (Side::Dire, hero_id) if dire_idx < dire.len() => {
+                    dire[dire_idx] = hero_id;
+                    dire_idx += 1;
+                }
+                _ => {}
+            }
+        }
+        Self {
+            match_id,
+            radiant: (radiant[0], radiant[1], radiant[2], radiant[3], radiant[4]),
+            dire: (dire[0], dire[1], dire[2], dire[3], dire[4]),
+            radiant_win: Some(value.radiant_win),
+        }
+    }
+}
+
+/// Aggregate result of [`Database::matchup_stats`](crate::database::Database::matchup_stats).
+#[derive(Row, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MatchupStats {
+    pub games: u64,
+    pub hero_a_wins: u64,
 }
\ No newline at end of file
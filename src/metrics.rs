@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Counters and gauges exported over a tiny Prometheus text-format HTTP
+/// endpoint, so operators can watch and alert on a stalled or throttled
+/// collector without grepping debug logs.
+#[derive(Default)]
+pub struct Metrics {
+    matches_collected: AtomicU64,
+    decode_errors: AtomicU64,
+    connection_errors: AtomicU64,
+    rate_limits: AtomicU64,
+    last_seq_num: AtomicU64,
+    insert_latency_ms: AtomicU64,
+    rate_intervals: Mutex<HashMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn record_matches(&self, count: u64) {
+        self.matches_collected.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limit(&self) {
+        self.rate_limits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_last_seq_num(&self, seq_num: u64) {
+        self.last_seq_num.store(seq_num, Ordering::Relaxed);
+    }
+
+    pub fn record_insert_latency(&self, latency: Duration) {
+        self.insert_latency_ms
+            .store(latency.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_rate_interval(&self, key: &str, interval: Duration) {
+        self.rate_intervals
+            .lock()
+            .expect("rate_intervals mutex poisoned")
+            .insert(key.to_string(), interval.as_millis() as u64);
+    }
+
+    fn encode(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP dota2_collector_matches_total Matches collected since start.\n");
+        out.push_str("# TYPE dota2_collector_matches_total counter\n");
+        out.push_str(&format!(
+            "dota2_collector_matches_total {}\n",
+            self.matches_collected.load(Ordering::Relaxed)
+        ));
+
+        out.push_str("# HELP dota2_collector_decode_errors_total Decode errors since start.\n");
+        out.push_str("# TYPE dota2_collector_decode_errors_total counter\n");
+        out.push_str(&format!(
+            "dota2_collector_decode_errors_total {}\n",
+            self.decode_errors.load(Ordering::Relaxed)
+        ));
+
+        out.push_str(
+            "# HELP dota2_collector_connection_errors_total Connection errors since start.\n",
+        );
+        out.push_str("# TYPE dota2_collector_connection_errors_total counter\n");
+        out.push_str(&format!(
+            "dota2_collector_connection_errors_total {}\n",
+            self.connection_errors.load(Ordering::Relaxed)
+        ));
+
+        out.push_str("# HELP dota2_collector_rate_limits_total Rate-limit responses since start.\n");
+        out.push_str("# TYPE dota2_collector_rate_limits_total counter\n");
+        out.push_str(&format!(
+            "dota2_collector_rate_limits_total {}\n",
+            self.rate_limits.load(Ordering::Relaxed)
+        ));
+
+        out.push_str("# HELP dota2_collector_last_seq_num Highest match_seq_num saved so far.\n");
+        out.push_str("# TYPE dota2_collector_last_seq_num gauge\n");
+        out.push_str(&format!(
+            "dota2_collector_last_seq_num {}\n",
+            self.last_seq_num.load(Ordering::Relaxed)
+        ));
+
+        out.push_str(
+            "# HELP dota2_collector_insert_latency_ms Latency of the last ClickHouse insert.\n",
+        );
+        out.push_str("# TYPE dota2_collector_insert_latency_ms gauge\n");
+        out.push_str(&format!(
+            "dota2_collector_insert_latency_ms {}\n",
+            self.insert_latency_ms.load(Ordering::Relaxed)
+        ));
+
+        out.push_str(
+            "# HELP dota2_collector_rate_control_interval_ms Current per-key rate-control interval.\n",
+        );
+        out.push_str("# TYPE dota2_collector_rate_control_interval_ms gauge\n");
+        for (key, interval) in self
+            .rate_intervals
+            .lock()
+            .expect("rate_intervals mutex poisoned")
+            .iter()
+        {
+            out.push_str(&format!(
+                "dota2_collector_rate_control_interval_ms{{key=\"{}\"}} {}\n",
+                key, interval
+            ));
+        }
+
+        out
+    }
+
+    /// Spawns an HTTP server on `addr` that serves the metrics above in
+    /// Prometheus text format at `/metrics`, for as long as the process runs.
+    pub fn serve(self: Arc<Self>, addr: SocketAddr) {
+        tokio::spawn(async move {
+            let listener = match tokio::net::TcpListener::bind(addr).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    log::error!("failed to bind metrics endpoint on {}: {}", addr, err);
+                    return;
+                }
+            };
+            log::info!("serving metrics on http://{}/metrics", addr);
+            loop {
+                let Ok((socket, _)) = listener.accept().await else {
+                    continue;
+                };
+                let metrics = self.clone();
+                tokio::spawn(metrics.handle(socket));
+            }
+        });
+    }
+
+    async fn handle(self: Arc<Self>, mut socket: tokio::net::TcpStream) {
+        // we don't care which path or method was requested, there's only
+        // one thing to serve here
+        let mut discard = [0u8; 1024];
+        if socket.read(&mut discard).await.is_err() {
+            return;
+        }
+        let body = self.encode();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let _ = socket.write_all(response.as_bytes()).await;
+    }
+}